@@ -1,51 +1,229 @@
 #[macro_use]
 extern crate log;
+extern crate chrono;
 extern crate env_logger;
+extern crate libc;
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::env;
+use std::fs;
 use std::fs::File;
 use std::io::{self, Write};
+use std::panic;
 use std::sync::atomic;
+use std::sync::OnceLock;
 use std::thread;
 
 use env_logger::filter::{Builder, Filter};
-use log::{LevelFilter, Metadata, Record};
+use log::{Level, LevelFilter, Metadata, Record};
 
 static INITIALIZED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+static PANIC_HOOK_INSTALLED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+static EXIT_FLUSH_INSTALLED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// The parts of a `LogConfig` needed after setup, shared across every thread. Set once, by
+/// whichever of `initialize()`/`initialize_with()` wins the race to call `set_boxed_logger()`.
+static CONFIG: OnceLock<SharedConfig> = OnceLock::new();
+
+struct SharedConfig {
+    filename_prefix: String,
+    also_log_to_stderr: bool,
+    formatter: Formatter,
+    ring_buffer_capacity: usize,
+    memory_level: Level,
+    max_bytes: u64,
+    max_generations: usize,
+}
+
+/// Per-thread state: the thread's own file and its path (needed to rotate it), the number of
+/// bytes written to it so far, plus (if a ring buffer is configured) the most recent formatted
+/// records at or below the memory level, not yet written to the file.
+struct ThreadState {
+    path: String,
+    writer: io::BufWriter<File>,
+    bytes_written: u64,
+    ring: VecDeque<Vec<u8>>,
+}
+
+impl Drop for ThreadState {
+    /// Flushes any buffered data when the thread exits, so a `BufWriter` with no pending
+    /// `flush()` call doesn't silently drop its tail of output.
+    fn drop(&mut self) {
+        drain_ring_and_flush(self);
+    }
+}
+
+/// Writes out any ring-buffered records still held by `state`, then flushes its writer. Shared by
+/// every path that needs to guarantee a thread's buffered output reaches disk: normal thread
+/// exit (`ThreadState::drop`), a panic on this thread (`flush_ring_buffer_on_panic`), and process
+/// exit (`flush_current_thread_writer`).
+fn drain_ring_and_flush(state: &mut ThreadState) {
+    for buffered in state.ring.drain(..) {
+        state.bytes_written += buffered.len() as u64;
+        let _ = state.writer.write_all(&buffered);
+    }
+    let _ = state.writer.flush();
+}
 
 thread_local! {
-    static WRITER: RefCell<Option<io::BufWriter<File>>> = RefCell::new(None);
+    static WRITER: RefCell<Option<ThreadState>> = RefCell::new(None);
 }
 
-/// Initializes the current process/thread with a logger, parsing the RUST_LOG environment
-/// variables to set the logging level filter and/or directives to set a filter by module name,
-/// following the usual env_logger conventions.
+/// A closure that formats a log record and writes it to the given writer, in the style of
+/// `env_logger`'s own formatter callback.
+pub type Formatter = Box<dyn Fn(&mut dyn Write, &Record) -> io::Result<()> + Sync + Send>;
+
+/// Configuration for the file-per-thread logger.
 ///
-/// Must be called on every running thread, or else logging will panic the first time it's used.
-pub fn initialize(filename_prefix: &str) {
-    let level_filter = env::var_os("RUST_LOG").map(|val| {
-        let mut builder = Builder::new();
-        builder.parse(&val.to_str().unwrap());
-        builder.build()
-    });
+/// Build one with `LogConfig::new()` and the builder methods below, then hand it to
+/// `initialize_with()`. Callers who just want the historical `RUST_LOG`-driven behavior can keep
+/// calling `initialize()` instead.
+pub struct LogConfig {
+    filename_prefix: String,
+    filter: Option<String>,
+    also_log_to_stderr: bool,
+    formatter: Formatter,
+    ring_buffer_capacity: usize,
+    memory_level: Level,
+    max_bytes: u64,
+    max_generations: usize,
+}
 
-    if level_filter.is_some() {
-        // Ensure the thread local state is always properly initialized.
-        WRITER.with(|rc| {
-            if rc.borrow().is_none() {
-                rc.replace(Some(open_file(filename_prefix)));
-            }
-        });
+impl LogConfig {
+    /// Creates a new configuration that writes files named with the given prefix.
+    ///
+    /// By default the filter is taken from `RUST_LOG`, output goes only to the per-thread files,
+    /// records are formatted with `default_formatter`, the ring buffer is disabled, and files are
+    /// never rotated.
+    pub fn new(filename_prefix: &str) -> Self {
+        LogConfig {
+            filename_prefix: filename_prefix.to_owned(),
+            filter: None,
+            also_log_to_stderr: false,
+            formatter: Box::new(default_formatter),
+            ring_buffer_capacity: 0,
+            memory_level: Level::Trace,
+            max_bytes: 0,
+            max_generations: 0,
+        }
     }
 
-    if INITIALIZED.load(atomic::Ordering::Relaxed) || level_filter.is_none() {
+    /// Sets an explicit filter directive string, following the usual `env_logger` syntax.
+    ///
+    /// When set, this overrides `RUST_LOG` entirely; the environment variable is not consulted.
+    pub fn filter(mut self, directives: &str) -> Self {
+        self.filter = Some(directives.to_owned());
+        self
+    }
+
+    /// If set, also writes every log record to stderr, in addition to the thread's file.
+    pub fn also_log_to_stderr(mut self, enabled: bool) -> Self {
+        self.also_log_to_stderr = enabled;
+        self
+    }
+
+    /// Installs a custom formatter used to render each record before it's written out, replacing
+    /// `default_formatter`.
+    pub fn format<F>(mut self, formatter: F) -> Self
+    where
+        F: Fn(&mut dyn Write, &Record) -> io::Result<()> + Sync + Send + 'static,
+    {
+        self.formatter = Box::new(formatter);
+        self
+    }
+
+    /// Enables an in-memory ring buffer holding the last `capacity` formatted records whose
+    /// level is at or below `memory_level` (i.e. as verbose as, or more verbose than,
+    /// `memory_level` — `Level::Trace` is the usual choice). Buffered records are cheap: they
+    /// cost a format and a push, not a write. They're flushed to this thread's file the moment a
+    /// more severe record arrives on the same thread, or when the thread panics, so the lead-up
+    /// to a crash is captured without paying I/O cost for trace volume during normal operation.
+    pub fn ring_buffer(mut self, capacity: usize, memory_level: Level) -> Self {
+        self.ring_buffer_capacity = capacity;
+        self.memory_level = memory_level;
+        self
+    }
+
+    /// Enables size-based rotation: once the current thread's file exceeds `max_bytes`, it's
+    /// closed, renamed with a numeric suffix (`prefix.thread.1`, `.2`, …, keeping at most
+    /// `max_generations` old generations), and a fresh file is opened in its place.
+    pub fn rotate(mut self, max_bytes: u64, max_generations: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self.max_generations = max_generations;
+        self
+    }
+}
+
+/// The formatter used by default: an RFC3339 timestamp, the record's target, its level, and its
+/// message. Per-thread files only make sense to compare against each other if they can be lined
+/// up in time, so the default includes a timestamp even though the original hardcoded format did
+/// not.
+pub fn default_formatter(writer: &mut dyn Write, record: &Record) -> io::Result<()> {
+    writeln!(
+        writer,
+        "{} {} {} - {}",
+        chrono::Utc::now().to_rfc3339(),
+        record.target(),
+        record.level(),
+        record.args()
+    )
+}
+
+/// Initializes the logger, parsing the RUST_LOG environment variable to set the logging level
+/// filter and/or directives to set a filter by module name, following the usual env_logger
+/// conventions.
+///
+/// Does not need to be called on every thread: any thread that logs opens its own file the first
+/// time it does so.
+pub fn initialize(filename_prefix: &str) {
+    initialize_with(LogConfig::new(filename_prefix))
+}
+
+/// Initializes the logger, using the given `LogConfig`. `RUST_LOG` is read only when the config
+/// sets no explicit filter; if it has none and `RUST_LOG` is unset, this does nothing.
+///
+/// Does not need to be called on every thread: any thread that logs opens its own file the first
+/// time it does so, using the filename prefix and formatter recorded in `config`.
+pub fn initialize_with(config: LogConfig) {
+    let level_filter = match config.filter {
+        Some(ref directives) => {
+            let mut builder = Builder::new();
+            builder.parse(directives);
+            Some(builder.build())
+        }
+        None => env::var_os("RUST_LOG").map(|val| {
+            let mut builder = Builder::new();
+            builder.parse(&val.to_str().unwrap());
+            builder.build()
+        }),
+    };
+
+    let level_filter = match level_filter {
+        Some(level_filter) => level_filter,
+        None => return,
+    };
+
+    if INITIALIZED.swap(true, atomic::Ordering::Relaxed) {
         return;
     }
 
-    INITIALIZED.store(true, atomic::Ordering::Relaxed);
+    let filename_prefix = config.filename_prefix.clone();
+    if config.ring_buffer_capacity > 0 {
+        install_panic_hook();
+    }
+    install_exit_flush();
+    let _ = CONFIG.set(SharedConfig {
+        filename_prefix: config.filename_prefix,
+        also_log_to_stderr: config.also_log_to_stderr,
+        formatter: config.formatter,
+        ring_buffer_capacity: config.ring_buffer_capacity,
+        memory_level: config.memory_level,
+        max_bytes: config.max_bytes,
+        max_generations: config.max_generations,
+    });
 
-    let logger = FilePerThreadLogger::new(level_filter.unwrap());
+    let logger = FilePerThreadLogger::new(level_filter);
     let setup_result =
         log::set_boxed_logger(Box::new(logger)).map(|()| log::set_max_level(LevelFilter::max()));
     match setup_result {
@@ -75,35 +253,176 @@ impl log::Log for FilePerThreadLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            WRITER.with(|rc| {
-                let mut opt_writer = rc.borrow_mut();
-                let writer = opt_writer
-                    .as_mut()
-                    .expect("call the logger's initialize() function first");
-                let _ = writeln!(*writer, "{} - {}", record.level(), record.args());
-            })
+            let config = CONFIG.get().expect("logger not initialized");
+
+            let mut formatted = Vec::new();
+            let _ = (config.formatter)(&mut formatted, record);
+
+            with_current_thread_state(config, |state| {
+                record_to_thread(config, state, record.level(), &formatted);
+            });
+
+            if config.also_log_to_stderr {
+                let _ = io::stderr().write_all(&formatted);
+            }
         }
     }
 
     fn flush(&self) {
-        WRITER.with(|rc| {
-            let mut opt_writer = rc.borrow_mut();
-            let writer = opt_writer
-                .as_mut()
-                .expect("call the logger's initialize() function first");
-            let _ = writer.flush();
-        });
+        if let Some(config) = CONFIG.get() {
+            with_current_thread_state(config, |state| {
+                let _ = state.writer.flush();
+            });
+        }
+    }
+}
+
+/// Runs `f` against the current thread's state, opening its file on demand the first time this
+/// thread logs.
+fn with_current_thread_state<R>(
+    config: &SharedConfig,
+    f: impl FnOnce(&mut ThreadState) -> R,
+) -> R {
+    WRITER.with(|rc| {
+        let mut opt_state = rc.borrow_mut();
+        if opt_state.is_none() {
+            let path = thread_file_path(&config.filename_prefix);
+            opt_state.replace(ThreadState {
+                writer: open_file(&path),
+                path,
+                bytes_written: 0,
+                ring: VecDeque::new(),
+            });
+        }
+        f(opt_state.as_mut().unwrap())
+    })
+}
+
+/// Records one already-formatted line against a thread's state: buffers it in the ring if its
+/// level is at or below `config.memory_level`, otherwise drains the ring and writes it straight
+/// through, rotating the file afterwards if it's grown past the configured limit.
+fn record_to_thread(
+    config: &SharedConfig,
+    state: &mut ThreadState,
+    level: Level,
+    formatted: &[u8],
+) {
+    if config.ring_buffer_capacity > 0 && level >= config.memory_level {
+        if state.ring.len() >= config.ring_buffer_capacity {
+            state.ring.pop_front();
+        }
+        state.ring.push_back(formatted.to_vec());
+    } else {
+        for buffered in state.ring.drain(..) {
+            state.bytes_written += buffered.len() as u64;
+            let _ = state.writer.write_all(&buffered);
+        }
+        state.bytes_written += formatted.len() as u64;
+        let _ = state.writer.write_all(formatted);
+        rotate_if_needed(config, state);
+    }
+}
+
+/// Rotates the current thread's file if it has grown past `config.max_bytes`.
+fn rotate_if_needed(config: &SharedConfig, state: &mut ThreadState) {
+    if config.max_bytes == 0 || state.bytes_written <= config.max_bytes {
+        return;
+    }
+
+    let _ = state.writer.flush();
+    rotate_file(&state.path, config.max_generations);
+    state.writer = open_file(&state.path);
+    state.bytes_written = 0;
+}
+
+/// Shifts `path.1`, `path.2`, … up by one generation (dropping anything past
+/// `max_generations`), then renames `path` itself to `path.1`.
+fn rotate_file(path: &str, max_generations: usize) {
+    if max_generations == 0 {
+        let _ = fs::remove_file(path);
+        return;
+    }
+
+    let _ = fs::remove_file(format!("{}.{}", path, max_generations));
+    for generation in (1..max_generations).rev() {
+        let _ = fs::rename(
+            format!("{}.{}", path, generation),
+            format!("{}.{}", path, generation + 1),
+        );
+    }
+    let _ = fs::rename(path, format!("{}.1", path));
+}
+
+/// Installs a panic hook (chained after whichever hook was previously installed) that flushes
+/// this thread's ring buffer to its file before the thread unwinds, so trace-level context
+/// leading up to a panic isn't lost.
+fn install_panic_hook() {
+    if PANIC_HOOK_INSTALLED.swap(true, atomic::Ordering::Relaxed) {
+        return;
+    }
+
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous_hook(info);
+        flush_ring_buffer_on_panic();
+    }));
+}
+
+/// Writes out the panicking thread's buffered ring, if it has one.
+fn flush_ring_buffer_on_panic() {
+    WRITER.with(|rc| {
+        if let Some(state) = rc.borrow_mut().as_mut() {
+            drain_ring_and_flush(state);
+        }
+    });
+}
+
+/// Registers an `atexit` handler that flushes the calling thread's buffered data. `ThreadState`'s
+/// `Drop` impl already handles normal thread exit, but nothing runs thread-local destructors when
+/// a thread calls `std::process::exit()` instead of returning, so the main thread (where that's
+/// most often called) needs this extra guarantee.
+fn install_exit_flush() {
+    if EXIT_FLUSH_INSTALLED.swap(true, atomic::Ordering::Relaxed) {
+        return;
+    }
+
+    // Called through an `extern "C" fn`, so nothing here may be allowed to unwind; wrap it in
+    // `catch_unwind` for safety.
+    extern "C" fn flush_current_thread() {
+        let _ = panic::catch_unwind(flush_current_thread_writer);
+    }
+
+    unsafe {
+        libc::atexit(flush_current_thread);
     }
 }
 
+/// Flushes the calling thread's writer, if it still has one, draining any buffered ring-buffer
+/// records into it first.
+///
+/// By the time `atexit` handlers run, the calling thread's thread-locals may already be torn
+/// down (this is the normal case for the main thread returning from `main()`), so this uses
+/// `try_with`/`try_borrow_mut` and silently does nothing rather than panicking.
+fn flush_current_thread_writer() {
+    let _ = WRITER.try_with(|rc| {
+        let mut opt_state = match rc.try_borrow_mut() {
+            Ok(opt_state) => opt_state,
+            Err(_) => return,
+        };
+        if let Some(state) = opt_state.as_mut() {
+            drain_ring_and_flush(state);
+        }
+    });
+}
+
 /// Checks whether the logging state has ever been initialized or not.
 #[inline]
 fn enabled() -> bool {
     INITIALIZED.load(atomic::Ordering::Relaxed)
 }
 
-/// Open the tracing file for the current thread.
-fn open_file(filename_prefix: &str) -> io::BufWriter<File> {
+/// Computes the path of the tracing file for the current thread, given a filename prefix.
+fn thread_file_path(filename_prefix: &str) -> String {
     let curthread = thread::current();
     let tmpstr;
     let mut path = filename_prefix.to_owned();
@@ -117,6 +436,207 @@ fn open_file(filename_prefix: &str) -> io::BufWriter<File> {
             }
         }.filter(|ch| ch.is_alphanumeric() || *ch == '-' || *ch == '_'),
     );
+    path
+}
+
+/// Open the tracing file at `path`, truncating it if it already exists.
+fn open_file(path: &str) -> io::BufWriter<File> {
     let file = File::create(path).expect("Can't open tracing file");
     io::BufWriter::new(file)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// A path under the system temp dir, unique to this process and the given name.
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("file-per-thread-logger-test-{}-{}", process::id(), name))
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    fn default_config() -> SharedConfig {
+        SharedConfig {
+            filename_prefix: String::new(),
+            also_log_to_stderr: false,
+            formatter: Box::new(default_formatter),
+            ring_buffer_capacity: 0,
+            memory_level: Level::Trace,
+            max_bytes: 0,
+            max_generations: 0,
+        }
+    }
+
+    #[test]
+    fn ring_buffer_evicts_oldest_and_flushes_on_more_severe_record() {
+        let path = temp_path("ring");
+        let mut config = default_config();
+        config.ring_buffer_capacity = 2;
+        config.memory_level = Level::Debug;
+
+        let mut state = ThreadState {
+            writer: open_file(&path),
+            path: path.clone(),
+            bytes_written: 0,
+            ring: VecDeque::new(),
+        };
+
+        record_to_thread(&config, &mut state, Level::Trace, b"trace-1\n");
+        record_to_thread(&config, &mut state, Level::Trace, b"trace-2\n");
+        record_to_thread(&config, &mut state, Level::Trace, b"trace-3\n");
+        assert_eq!(
+            state.ring.iter().cloned().collect::<Vec<_>>(),
+            vec![b"trace-2\n".to_vec(), b"trace-3\n".to_vec()],
+            "the oldest buffered record should have been evicted to stay at capacity"
+        );
+
+        // Info is more severe than the Debug memory level, so it flushes the ring straight
+        // through along with itself.
+        record_to_thread(&config, &mut state, Level::Info, b"info-1\n");
+        assert!(state.ring.is_empty());
+        state.writer.flush().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "trace-2\ntrace-3\ninfo-1\n"
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rotate_file_shifts_generations_and_drops_oldest() {
+        let path = temp_path("rotate");
+        let gen1 = format!("{}.1", path);
+        let gen2 = format!("{}.2", path);
+
+        fs::write(&path, "current").unwrap();
+        fs::write(&gen1, "gen1").unwrap();
+        fs::write(&gen2, "gen2").unwrap();
+
+        rotate_file(&path, 2);
+
+        assert!(!std::path::Path::new(&path).exists());
+        assert_eq!(fs::read_to_string(&gen1).unwrap(), "current");
+        assert_eq!(fs::read_to_string(&gen2).unwrap(), "gen1");
+        assert!(!std::path::Path::new(&format!("{}.3", path)).exists());
+
+        let _ = fs::remove_file(&gen1);
+        let _ = fs::remove_file(&gen2);
+    }
+
+    #[test]
+    fn rotate_if_needed_resets_byte_count_after_rotating() {
+        let path = temp_path("rotate-bytes");
+        let mut config = default_config();
+        config.max_bytes = 4;
+        config.max_generations = 1;
+
+        let mut state = ThreadState {
+            writer: open_file(&path),
+            path: path.clone(),
+            bytes_written: 10,
+            ring: VecDeque::new(),
+        };
+
+        rotate_if_needed(&config, &mut state);
+
+        assert_eq!(state.bytes_written, 0);
+        assert!(std::path::Path::new(&format!("{}.1", path)).exists());
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.1", path));
+    }
+
+    #[test]
+    fn exit_flush_writes_data_still_sitting_in_the_buffer() {
+        let path = temp_path("exit-flush");
+        WRITER.with(|rc| {
+            rc.borrow_mut().replace(ThreadState {
+                writer: open_file(&path),
+                path: path.clone(),
+                bytes_written: 0,
+                ring: VecDeque::new(),
+            });
+        });
+
+        WRITER.with(|rc| {
+            rc.borrow_mut()
+                .as_mut()
+                .unwrap()
+                .writer
+                .write_all(b"pending\n")
+                .unwrap();
+        });
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        flush_current_thread_writer();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "pending\n");
+
+        WRITER.with(|rc| {
+            rc.borrow_mut().take();
+        });
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn exit_flush_drains_the_ring_buffer_too() {
+        let path = temp_path("exit-flush-ring");
+        WRITER.with(|rc| {
+            rc.borrow_mut().replace(ThreadState {
+                writer: open_file(&path),
+                path: path.clone(),
+                bytes_written: 0,
+                ring: VecDeque::from(vec![b"trace-1\n".to_vec(), b"trace-2\n".to_vec()]),
+            });
+        });
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        flush_current_thread_writer();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "trace-1\ntrace-2\n");
+        WRITER.with(|rc| {
+            assert!(rc.borrow().as_ref().unwrap().ring.is_empty());
+        });
+
+        WRITER.with(|rc| {
+            rc.borrow_mut().take();
+        });
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn exit_flush_is_a_no_op_without_a_writer() {
+        WRITER.with(|rc| {
+            rc.borrow_mut().take();
+        });
+        flush_current_thread_writer();
+    }
+
+    #[test]
+    fn uninitialized_thread_can_log_lazily_after_initialize_with() {
+        let prefix = temp_path("init-");
+        initialize_with(LogConfig::new(&prefix).filter("trace"));
+
+        let expected_path = format!("{}worker", prefix);
+        thread::Builder::new()
+            .name("worker".to_owned())
+            .spawn(|| {
+                // This thread never calls `initialize`/`initialize_with` itself; its file should
+                // still be opened lazily on first use, the way chunk0-3 intended.
+                info!("hello from worker");
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert!(fs::read_to_string(&expected_path)
+            .unwrap()
+            .contains("hello from worker"));
+
+        let _ = fs::remove_file(&expected_path);
+    }
+}